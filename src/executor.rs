@@ -0,0 +1,194 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Wake, Waker},
+};
+
+use crossbeam::channel;
+
+/// A minimal single-threaded executor for driving futures (in
+/// particular `RayonFuture`s) without pulling in a full async runtime
+/// like `async-std` or `tokio`. Good enough when all a caller needs is
+/// rayon offload plus a tiny reactor.
+pub struct LocalExecutor {
+    ready_send: channel::Sender<Arc<Task>>,
+    ready_recv: channel::Receiver<Arc<Task>>,
+    pending: Arc<AtomicUsize>,
+}
+
+struct Task {
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    ready_send: channel::Sender<Arc<Task>>,
+    pending: Arc<AtomicUsize>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // The receiving end outlives every task, so this can only fail
+        // if the executor itself has already been dropped, in which
+        // case there's nobody left to deliver the wakeup to anyway.
+        let _ = self.ready_send.send(self.clone());
+    }
+}
+
+impl LocalExecutor {
+    pub fn new() -> Self {
+        let (ready_send, ready_recv) = channel::unbounded();
+        LocalExecutor {
+            ready_send,
+            ready_recv,
+            pending: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Box `fut` and push it onto the ready queue so `run`/`poll_once`
+    /// will drive it to completion.
+    pub fn spawn<F>(&self, fut: F)
+        where F: Future<Output = ()> + Send + 'static,
+    {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(fut))),
+            ready_send: self.ready_send.clone(),
+            pending: self.pending.clone(),
+        });
+        let _ = self.ready_send.send(task);
+    }
+
+    /// Block the calling thread, pumping the ready queue until every
+    /// spawned task has completed.
+    pub fn run(&self) {
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            if let Ok(task) = self.ready_recv.recv() {
+                Self::poll_task(&task);
+            }
+        }
+    }
+
+    /// Drain whatever tasks are *currently* ready without blocking.
+    /// Useful for embedding in a frame-based loop (e.g. a game loop)
+    /// that wants to make progress on spawned futures once per frame
+    /// rather than block until they're all done.
+    pub fn poll_once(&self) {
+        // Only take the tasks queued on entry; anything woken while we
+        // drain waits for the next call.
+        for _ in 0..self.ready_recv.len() {
+            match self.ready_recv.try_recv() {
+                Ok(task) => Self::poll_task(&task),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn poll_task(task: &Arc<Task>) {
+        let mut slot = task.future.lock().expect("executor task lock");
+        if let Some(mut fut) = slot.take() {
+            let waker = Waker::from(task.clone());
+            let mut cx = Context::from_waker(&waker);
+            match fut.as_mut().poll(&mut cx) {
+                Poll::Ready(()) => {
+                    task.pending.fetch_sub(1, Ordering::SeqCst);
+                }
+                Poll::Pending => {
+                    *slot = Some(fut);
+                }
+            }
+        }
+    }
+}
+
+impl Default for LocalExecutor {
+    fn default() -> Self {
+        LocalExecutor::new()
+    }
+}
+
+/// Drive `f` to completion on a throwaway `LocalExecutor` and return its
+/// output. A convenience for callers who just want to run one future
+/// (typically a `RayonFuture`) without setting up an executor of their
+/// own.
+pub fn block_on<F>(f: F) -> F::Output
+    where F: Future + Send + 'static,
+          F::Output: Send + 'static,
+{
+    let output = Arc::new(Mutex::new(None));
+    let out_slot = output.clone();
+
+    let executor = LocalExecutor::new();
+    executor.spawn(async move {
+        let result = f.await;
+        *out_slot.lock().expect("block_on output lock") = Some(result);
+    });
+    executor.run();
+
+    output.lock()
+        .expect("block_on output lock")
+        .take()
+        .expect("LocalExecutor::run returned before the future completed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread::sleep, time::Duration};
+
+    use crate::spawn;
+
+    #[test]
+    fn run_drives_a_rayon_future_to_completion() {
+        let executor = LocalExecutor::new();
+        let result = Arc::new(Mutex::new(None));
+        let result2 = result.clone();
+
+        executor.spawn(async move {
+            let v = spawn(move || {
+                sleep(Duration::from_millis(20));
+                1
+            }).await;
+            *result2.lock().expect("result lock") = Some(v);
+        });
+        executor.run();
+
+        assert_eq!(*result.lock().expect("result lock"), Some(1));
+    }
+
+    #[test]
+    fn block_on_returns_the_futures_output() {
+        let v = block_on(spawn(move || {
+            sleep(Duration::from_millis(20));
+            1
+        }));
+        assert_eq!(v, 1);
+    }
+
+    #[test]
+    fn poll_once_only_drains_currently_ready_tasks() {
+        let executor = LocalExecutor::new();
+        let ran = Arc::new(Mutex::new(false));
+        let ran2 = ran.clone();
+
+        executor.spawn(async move {
+            spawn(move || {
+                sleep(Duration::from_millis(50));
+            }).await;
+            *ran2.lock().expect("ran lock") = true;
+        });
+
+        // `poll_once` polls the freshly spawned task once, which starts
+        // the job and parks; the job hasn't had time to finish yet, so
+        // the task isn't back on the ready queue and the flag stays unset.
+        executor.poll_once();
+        assert_eq!(*ran.lock().expect("ran lock"), false);
+
+        executor.run();
+        assert_eq!(*ran.lock().expect("ran lock"), true);
+    }
+}
@@ -1,74 +1,322 @@
 use std::{
-    sync::{Arc, Mutex},
+    cell::UnsafeCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     future::Future,
     pin::Pin,
     task::{Context, Poll, Waker},
+    thread,
 };
 
 use rayon;
-use crossbeam::channel;
 
-/// Immediately spawn the given computation and return a handle to its result
-pub fn spawn<T, F>(f: F) -> RayonFuture<T>
+mod stream;
+pub use stream::{spawn_stream, RayonStream, Sender};
+
+mod executor;
+pub use executor::{block_on, LocalExecutor};
+
+/// Immediately spawn the given computation and return a handle to its
+/// result. If `f` panics, the panic is caught on the rayon worker and
+/// resumed on whichever task polls the returned future, so a panic in
+/// the compute job looks like a normal panic in the polling task rather
+/// than an unrelated `"unexpected rayon future hangup"`.
+///
+/// See `spawn_catch_unwind` if you'd rather observe the panic as an
+/// `Err` instead of having it resumed for you, or `spawn_cancellable`
+/// if the job should be able to notice that its future was dropped.
+pub fn spawn<T, F>(f: F) -> impl Future<Output = T>
+    where F: FnOnce() -> T + Send + 'static,
+          T: Send + 'static,
+{
+    ResumingRayonFuture {
+        inner: spawn_catch_unwind(f),
+    }
+}
+
+/// Like `spawn`, but the returned future resolves to a `thread::Result`
+/// instead of resuming the panic for you, so the caller can decide how
+/// to handle a panicking job.
+pub fn spawn_catch_unwind<T, F>(f: F) -> RayonFuture<T>
     where F: FnOnce() -> T + Send + 'static,
           T: Send + 'static,
 {
-    let (send, recv) = channel::bounded(1);
+    spawn_job(move |_token| f())
+}
+
+/// Spawn a computation that can cooperatively notice cancellation. `f`
+/// is handed a `CancelToken` which it should check periodically (e.g.
+/// between chunks of a long loop) and bail out of early when
+/// `token.is_cancelled()` becomes true.
+///
+/// Rayon jobs can't be force-killed once they're running, so
+/// cancellation here is *cooperative*: dropping the returned future
+/// before it completes flips the token, but the job only stops once it
+/// actually checks. A job that never checks the token runs to
+/// completion regardless.
+pub fn spawn_cancellable<T, F>(f: F) -> RayonFuture<T>
+    where F: FnOnce(&CancelToken) -> T + Send + 'static,
+          T: Send + 'static,
+{
+    spawn_job(move |token| f(&token))
+}
+
+fn spawn_job<T, F>(f: F) -> RayonFuture<T>
+    where F: FnOnce(CancelToken) -> T + Send + 'static,
+          T: Send + 'static,
+{
+    let cancel = CancelToken::new();
+    let shared = Arc::new(Shared::new());
 
-    let fut = RayonFuture{
-        state: Arc::new(Mutex::new(State {
-            recv,
-            waker: None,
-        })),
+    let fut = RayonFuture {
+        shared: shared.clone(),
+        cancel: cancel.clone(),
     };
-    let fut_st = fut.state.clone();
+    let job_cancel = cancel;
 
     rayon::spawn(move || {
-        let result = f();
-        send.send(result).unwrap();
-
-        // check to see if the future has already been polled and is now
-        // waiting to get polled again.
-        let mut st = fut_st.lock().expect("rayon future lock");
-        if let Some(waker) = st.waker.take() {
-            waker.wake();
-        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(job_cancel)));
+        shared.send(result);
     });
 
     fut
 }
 
+/// A cooperative cancellation signal shared between a `RayonFuture` and
+/// the job it was spawned from. See `spawn_cancellable`.
+#[derive(Clone)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    fn new() -> Self {
+        CancelToken { cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// True once the `RayonFuture` this token belongs to has been
+    /// dropped. Jobs spawned with `spawn_cancellable` should check this
+    /// periodically and return early when it flips to `true`.
+    pub fn is_cancelled(&self) -> bool {
+        // Pairs with the `Release` store in `cancel`: this is a plain
+        // one-way flag, not a hand-off of other data, so `Acquire` is
+        // all that's needed (no `SeqCst` total order required).
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+}
+
 /// A future representing some compute intensive/blocking IO work that has
 /// been offloaded to the rayon thread pool. When the computation is done
-/// the future will complete.
+/// the future will complete with the job's result, or an `Err` holding
+/// the panic payload if the job panicked.
 pub struct RayonFuture<T> {
-    state: Arc<Mutex<State<T>>>,
+    shared: Arc<Shared<thread::Result<T>>>,
+    cancel: CancelToken,
 }
 
-/// internal future state gaurded by a lock
-struct State<T> {
-    recv: channel::Receiver<T>,
-    waker: Option<Waker>,
+/// A `RayonFuture` that resumes the job's panic on poll instead of
+/// handing it back as an `Err`. Returned by `spawn`.
+struct ResumingRayonFuture<T> {
+    inner: RayonFuture<T>,
 }
 
 impl<T> Future for RayonFuture<T> {
-    type Output = T;
+    type Output = thread::Result<T>;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
-        let mut st = self.state.lock().expect("rayon future lock");
-        match st.recv.try_recv() {
-            Ok(r) => Poll::Ready(r),
-            Err(channel::TryRecvError::Empty) => {
-                st.waker = Some(cx.waker().clone());
-                Poll::Pending
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<thread::Result<T>> {
+        self.shared.poll(cx)
+    }
+}
+
+impl<T> Drop for RayonFuture<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.shared.mark_rx_dropped();
+    }
+}
+
+/// States for `Shared::state`: the hand-off starts `EMPTY`, the worker
+/// reserves it as `SENDING` while it writes the value, then publishes
+/// `SENT`. If the `RayonFuture` is dropped first it CASes straight from
+/// `EMPTY` to `RXDROPPED` so the worker knows to discard its result
+/// instead of writing into a cell nobody will ever read.
+const EMPTY: usize = 0;
+const SENDING: usize = 1;
+const SENT: usize = 2;
+const RXDROPPED: usize = 3;
+
+/// A single-slot, lock-free hand-off from the rayon worker to whatever
+/// polls the `RayonFuture`, modeled on `futures-channel::oneshot`.
+struct Shared<T> {
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+    waker: AtomicWaker,
+}
+
+// Safety: `value` is only ever written by the single worker that owns
+// the `SENDING` transition, and only ever read after observing `SENT`
+// via an `Acquire` load, so there is never a concurrent read/write.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn new() -> Self {
+        Shared {
+            state: AtomicUsize::new(EMPTY),
+            value: UnsafeCell::new(None),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Called once, from the rayon worker, when the job is done.
+    fn send(&self, value: T) {
+        match self.state.compare_exchange(EMPTY, SENDING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe { *self.value.get() = Some(value); }
+                self.state.store(SENT, Ordering::Release);
+                self.waker.wake();
+            }
+            Err(_) => {
+                // RXDROPPED: the future is gone, nobody is listening.
+                // Just let `value` drop here instead of delivering it.
+            }
+        }
+    }
+
+    /// Called from `RayonFuture::poll`.
+    fn poll(&self, cx: &mut Context) -> Poll<T> {
+        if let Some(v) = self.try_take() {
+            return Poll::Ready(v);
+        }
+        self.waker.register(cx.waker());
+        // The worker may have sent between our check above and
+        // registering the waker; check once more so we can't miss a
+        // wakeup that raced us here.
+        if let Some(v) = self.try_take() {
+            return Poll::Ready(v);
+        }
+        Poll::Pending
+    }
+
+    fn try_take(&self) -> Option<T> {
+        if self.state.load(Ordering::Acquire) == SENT {
+            unsafe { (*self.value.get()).take() }
+        } else {
+            None
+        }
+    }
+
+    /// Called from `RayonFuture::drop`.
+    fn mark_rx_dropped(&self) {
+        loop {
+            match self.state.compare_exchange(EMPTY, RXDROPPED, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return,
+                Err(SENDING) => {
+                    // The worker is mid-write; spin until it publishes
+                    // `SENT` so we can safely reclaim the value below.
+                    std::hint::spin_loop();
+                }
+                Err(SENT) => {
+                    // The value was already delivered but nobody will
+                    // ever poll for it; drop it here instead of leaking.
+                    unsafe { (*self.value.get()).take(); }
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// A single-waker parking slot, lock-free in the uncontended case: one
+/// task registers a waker, one worker wakes it. Same shape as
+/// `futures::task::AtomicWaker`.
+pub(crate) struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+unsafe impl Send for AtomicWaker {}
+unsafe impl Sync for AtomicWaker {}
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 0b01;
+const WAKING: usize = 0b10;
+
+impl AtomicWaker {
+    pub(crate) fn new() -> Self {
+        AtomicWaker {
+            state: AtomicUsize::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    pub(crate) fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(WAITING, REGISTERING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe { *self.waker.get() = Some(waker.clone()); }
+                match self.state.compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => {}
+                    Err(_) => {
+                        // A `wake()` landed on us mid-registration; take
+                        // the waker back and fire it instead of leaving
+                        // a stale one parked here.
+                        let waker = unsafe { (*self.waker.get()).take() };
+                        self.state.store(WAITING, Ordering::Release);
+                        if let Some(waker) = waker {
+                            waker.wake();
+                        }
+                    }
+                }
+            }
+            Err(state) if state & WAKING != 0 => {
+                // A wake is already in flight: the poller's next check
+                // will see the completed value, no need to park.
+                waker.wake_by_ref();
+            }
+            Err(_) => {
+                // Another registration is already in progress. This
+                // crate only ever has one poller at a time, so this is
+                // unexpected, but wake eagerly rather than risk losing
+                // the notification.
+                waker.wake_by_ref();
             }
-            Err(channel::TryRecvError::Disconnected) => {
-                panic!("unexpected rayon future hangup");
+        }
+    }
+
+    pub(crate) fn wake(&self) {
+        if let WAITING = self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKING, Ordering::AcqRel);
+            if let Some(waker) = waker {
+                waker.wake();
             }
         }
     }
 }
 
+impl<T> Future for ResumingRayonFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        // `inner`'s only fields are an `Arc<Shared<..>>` and a
+        // `CancelToken` (an `Arc<AtomicBool>`), both `Unpin`
+        // regardless of `T`, so `RayonFuture<T>` is `Unpin` for every
+        // `T` and this projection needs no unsafe code.
+        let inner = Pin::new(&mut self.get_mut().inner);
+        inner.poll(cx).map(|res| match res {
+            Ok(v) => v,
+            Err(payload) => panic::resume_unwind(payload),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,4 +345,61 @@ mod tests {
         let res = async_std::task::block_on(h);
         assert_eq!(res, 1);
     }
+
+    #[test]
+    fn it_catches_a_panicking_job() {
+        let h = async_std::task::spawn(spawn_catch_unwind(move || -> i32 {
+            panic!("boom");
+        }));
+        let res = async_std::task::block_on(h);
+        let payload = res.expect_err("panicking job should resolve to Err");
+        let msg = payload.downcast_ref::<&str>().expect("panic payload should be a &str");
+        assert_eq!(*msg, "boom");
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn spawn_resumes_the_panic_on_the_polling_task() {
+        let h = async_std::task::spawn(spawn(move || -> i32 {
+            panic!("boom");
+        }));
+        async_std::task::block_on(h);
+    }
+
+    #[test]
+    fn dropping_the_future_sets_the_cancel_token() {
+        use std::sync::mpsc;
+
+        let (saw_cancel_send, saw_cancel_recv) = mpsc::channel();
+        let fut = spawn_cancellable(move |token: &CancelToken| {
+            while !token.is_cancelled() {
+                sleep(Duration::from_millis(5));
+            }
+            saw_cancel_send.send(()).unwrap();
+        });
+
+        sleep(Duration::from_millis(20));
+        drop(fut);
+
+        saw_cancel_recv
+            .recv_timeout(Duration::from_millis(500))
+            .expect("job should notice cancellation and return");
+    }
+
+    #[test]
+    fn stress_the_send_poll_race() {
+        // Hammer both orderings of the send/poll race: sometimes the
+        // worker finishes and calls `send` before the async task ever
+        // polls, sometimes the task is already parked and waiting when
+        // `send` happens. Either way every job's result must show up.
+        for i in 0..500 {
+            let h = async_std::task::spawn(spawn(move || i));
+            if i % 2 == 0 {
+                // give the worker a head start so `send` usually wins
+                sleep(Duration::from_micros(50));
+            }
+            let res = async_std::task::block_on(h);
+            assert_eq!(res, i);
+        }
+    }
 }
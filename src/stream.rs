@@ -0,0 +1,191 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use rayon;
+use crossbeam::channel;
+use futures::Stream;
+
+use crate::AtomicWaker;
+
+/// How many items a `RayonStream` will buffer before `Sender::send`
+/// starts blocking the producing rayon job. Fixed rather than
+/// configurable, picked just large enough to smooth over a consumer
+/// that's briefly slower than the producer without letting a fast
+/// producer run arbitrarily far ahead.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Spawn a rayon job that produces a stream of values instead of a
+/// single aggregate result. `f` is handed a `Sender<T>` to push values
+/// to as it computes them; the returned `RayonStream<T>` yields each
+/// value as it arrives and ends once `f` returns and its `Sender` is
+/// dropped.
+///
+/// The channel between the job and the stream is bounded, so a
+/// producer that's faster than its consumer blocks in `Sender::send`
+/// until the consumer catches up, exerting backpressure on the rayon
+/// job instead of buffering an unbounded backlog.
+pub fn spawn_stream<T, F>(f: F) -> RayonStream<T>
+    where F: FnOnce(Sender<T>) + Send + 'static,
+          T: Send + 'static,
+{
+    let (send, recv) = channel::bounded(CHANNEL_CAPACITY);
+    let waker = Arc::new(AtomicWaker::new());
+    let panic = Arc::new(Mutex::new(None));
+
+    let sender = Sender {
+        inner: send.clone(),
+        waker: waker.clone(),
+    };
+    let guard = Sender {
+        inner: send,
+        waker: waker.clone(),
+    };
+    let job_panic = panic.clone();
+
+    rayon::spawn(move || {
+        // `sender` is dropped at the end of this call (or while
+        // unwinding, if `f` panics), but `guard` keeps the channel
+        // connected until a panic payload has been stashed, so the
+        // stream can tell "producer panicked" apart from "producer
+        // finished normally" instead of both just looking like a
+        // disconnected channel. Dropping `guard` then disconnects the
+        // channel and wakes the stream.
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| f(sender))) {
+            *job_panic.lock().expect("stream panic lock") = Some(payload);
+        }
+        drop(guard);
+    });
+
+    RayonStream { recv, waker, panic }
+}
+
+/// The sending half of a `RayonStream`, handed to the job spawned by
+/// `spawn_stream`.
+pub struct Sender<T> {
+    inner: channel::Sender<T>,
+    waker: Arc<AtomicWaker>,
+}
+
+impl<T> Sender<T> {
+    /// Push a value to the stream, blocking if the channel is full
+    /// until the consumer has drained some space.
+    pub fn send(&self, item: T) -> Result<(), channel::SendError<T>> {
+        let result = self.inner.send(item);
+        self.waker.wake();
+        result
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Wake the stream so it notices the channel disconnecting and
+        // ends rather than parking forever waiting for one last item.
+        // Our end of the channel is released first (fields would only
+        // drop after this method returns), so the wakeup can't arrive
+        // before the disconnect it announces.
+        let (detached, _) = channel::bounded(0);
+        drop(std::mem::replace(&mut self.inner, detached));
+        self.waker.wake();
+    }
+}
+
+/// A stream of results from a parallel producer spawned with
+/// `spawn_stream`. Any items sent before a producer panic are still
+/// delivered first; only once the channel drains and disconnects does
+/// polling the stream further resume the panic, so a consumer draining
+/// the stream with `while let Some(x) = stream.next().await` can't
+/// mistake a mid-stream panic for a clean finish.
+pub struct RayonStream<T> {
+    recv: channel::Receiver<T>,
+    waker: Arc<AtomicWaker>,
+    panic: Arc<Mutex<Option<Box<dyn Any + Send>>>>,
+}
+
+impl<T> Stream for RayonStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(item) = self.try_recv() {
+            return Poll::Ready(item);
+        }
+        self.waker.register(cx.waker());
+        // Unlike the oneshot `RayonFuture` core, this channel can have
+        // many `send`s land over the stream's lifetime, each one
+        // calling `wake()` independently; one of those sends (or the
+        // final `Sender` drop) may have arrived between our first
+        // `try_recv` and registering the waker just above, so check
+        // again before conceding `Pending`.
+        match self.try_recv() {
+            Some(item) => Poll::Ready(item),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> RayonStream<T> {
+    /// `Some(Some(item))` if a value was ready, `Some(None)` if the
+    /// stream is done, or `None` if neither has happened yet.
+    fn try_recv(&self) -> Option<Option<T>> {
+        match self.recv.try_recv() {
+            Ok(item) => Some(Some(item)),
+            Err(channel::TryRecvError::Empty) => None,
+            Err(channel::TryRecvError::Disconnected) => {
+                if let Some(payload) = self.panic.lock().expect("stream panic lock").take() {
+                    panic::resume_unwind(payload);
+                }
+                Some(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std;
+    use futures::StreamExt;
+
+    #[test]
+    fn it_streams_values_as_they_are_produced() {
+        let stream = spawn_stream(move |send| {
+            for i in 0..10 {
+                send.send(i).unwrap();
+            }
+        });
+
+        let items = async_std::task::block_on(stream.collect::<Vec<i32>>());
+        assert_eq!(items, (0..10).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn it_ends_when_the_sender_drops() {
+        let stream = spawn_stream(move |_send: Sender<i32>| {
+            // drop the sender immediately without sending anything
+        });
+
+        let items = async_std::task::block_on(stream.collect::<Vec<i32>>());
+        assert!(items.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn a_panicking_producer_panics_the_consumer_instead_of_ending_cleanly() {
+        let mut stream = spawn_stream(move |send: Sender<i32>| {
+            send.send(1).unwrap();
+            panic!("boom");
+        });
+
+        async_std::task::block_on(async {
+            assert_eq!(stream.next().await, Some(1));
+            // The producer panicked after sending its one item; this
+            // call should resume that panic rather than returning
+            // `None` like a normal end of stream.
+            stream.next().await;
+        });
+    }
+}